@@ -1,34 +1,107 @@
 use std::collections::{BTreeMap, HashSet};
 
+use blockifier::context::BlockContext;
 use blockifier::execution::entry_point::OrderedL2ToL1Message;
+use blockifier::state::cached_state::CachedState;
+use blockifier::state::state_api::StateReader;
+use blockifier::transaction::account_transaction::AccountTransaction;
+use blockifier::transaction::errors::TransactionExecutionError;
+use blockifier::transaction::transaction_execution::Transaction as ExecutableTransaction;
+use cairo_vm::types::builtin_name::BuiltinName;
 use pathfinder_common::{
     CasmHash, ClassHash, ContractAddress, ContractNonce, SierraHash, StorageAddress, StorageValue,
+    TransactionHash,
 };
 use pathfinder_crypto::Felt;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
 
 use super::felt::IntoFelt;
 
-#[derive(Debug)]
+/// Prefer [`FeeEstimate::from_resource_prices`] over a struct literal: it
+/// derives `overall_fee` from the other fields instead of requiring every
+/// call site to keep that arithmetic in sync by hand.
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct FeeEstimate {
+    #[serde_as(as = "pathfinder_serde::U256AsHexStr")]
     pub gas_consumed: primitive_types::U256,
+    #[serde_as(as = "pathfinder_serde::U256AsHexStr")]
     pub gas_price: primitive_types::U256,
+    /// L1 data-availability gas, billed separately from `gas_consumed` since
+    /// Starknet adopted 4844-style blob pricing for calldata.
+    #[serde_as(as = "pathfinder_serde::U256AsHexStr")]
+    pub data_gas_consumed: primitive_types::U256,
+    #[serde_as(as = "pathfinder_serde::U256AsHexStr")]
+    pub data_gas_price: primitive_types::U256,
+    #[serde_as(as = "pathfinder_serde::U256AsHexStr")]
     pub overall_fee: primitive_types::U256,
+    pub unit: PriceUnit,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+impl FeeEstimate {
+    /// Builds a [`FeeEstimate`], deriving `overall_fee` as
+    /// `gas_consumed * gas_price + data_gas_consumed * data_gas_price`.
+    pub fn from_resource_prices(
+        gas_consumed: primitive_types::U256,
+        gas_price: primitive_types::U256,
+        data_gas_consumed: primitive_types::U256,
+        data_gas_price: primitive_types::U256,
+        unit: PriceUnit,
+    ) -> Self {
+        let overall_fee = gas_consumed
+            .saturating_mul(gas_price)
+            .saturating_add(data_gas_consumed.saturating_mul(data_gas_price));
+
+        Self {
+            gas_consumed,
+            gas_price,
+            data_gas_consumed,
+            data_gas_price,
+            overall_fee,
+            unit,
+        }
+    }
+}
+
+/// The fee currency a [`FeeEstimate`] is denominated in: WEI for v0-v2
+/// (ETH-fee) transactions, FRI for v3 (STRK-fee) transactions.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum PriceUnit {
+    Wei,
+    Fri,
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum EntryPointType {
     Constructor,
     External,
     L1Handler,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct TransactionSimulation {
     pub trace: TransactionTrace,
     pub fee_estimation: FeeEstimate,
+    /// Stored alongside the trace so a `getEvents` query can skip this
+    /// transaction without scanning its `trace`'s events.
+    pub bloom: EventBloom,
+}
+
+impl TransactionSimulation {
+    /// Builds a [`TransactionSimulation`], deriving `bloom` from `trace` so
+    /// callers don't need to build it themselves.
+    pub fn new(trace: TransactionTrace, fee_estimation: FeeEstimate) -> Self {
+        let bloom = EventBloom::from_trace(&trace);
+        Self {
+            trace,
+            fee_estimation,
+            bloom,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum TransactionTrace {
     Declare(DeclareTransactionTrace),
     DeployAccount(DeployAccountTransactionTrace),
@@ -36,14 +109,14 @@ pub enum TransactionTrace {
     L1Handler(L1HandlerTransactionTrace),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct DeclareTransactionTrace {
     pub validate_invocation: Option<FunctionInvocation>,
     pub fee_transfer_invocation: Option<FunctionInvocation>,
     pub state_diff: StateDiff,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct DeployAccountTransactionTrace {
     pub validate_invocation: Option<FunctionInvocation>,
     pub constructor_invocation: Option<FunctionInvocation>,
@@ -51,13 +124,13 @@ pub struct DeployAccountTransactionTrace {
     pub state_diff: StateDiff,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum ExecuteInvocation {
     FunctionInvocation(Option<FunctionInvocation>),
     RevertedReason(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct InvokeTransactionTrace {
     pub validate_invocation: Option<FunctionInvocation>,
     pub execute_invocation: ExecuteInvocation,
@@ -65,49 +138,104 @@ pub struct InvokeTransactionTrace {
     pub state_diff: StateDiff,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct L1HandlerTransactionTrace {
     pub function_invocation: Option<FunctionInvocation>,
     pub state_diff: StateDiff,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum CallType {
     Call,
     Delegate,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[serde_as]
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Event {
     pub order: i64,
+    #[serde_as(as = "Vec<pathfinder_serde::FeltAsHexStr>")]
     pub data: Vec<Felt>,
+    #[serde_as(as = "Vec<pathfinder_serde::FeltAsHexStr>")]
     pub keys: Vec<Felt>,
 }
 
-#[derive(Debug)]
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct FunctionInvocation {
+    #[serde_as(as = "Vec<pathfinder_serde::FeltAsHexStr>")]
     pub calldata: Vec<Felt>,
     pub contract_address: ContractAddress,
+    #[serde_as(as = "pathfinder_serde::FeltAsHexStr")]
     pub selector: Felt,
     pub call_type: CallType,
+    #[serde_as(as = "pathfinder_serde::FeltAsHexStr")]
     pub caller_address: Felt,
     pub internal_calls: Vec<FunctionInvocation>,
+    #[serde_as(as = "Option<pathfinder_serde::FeltAsHexStr>")]
     pub class_hash: Option<Felt>,
     pub entry_point_type: EntryPointType,
     pub events: Vec<Event>,
     pub messages: Vec<MsgToL1>,
+    #[serde_as(as = "Vec<pathfinder_serde::FeltAsHexStr>")]
     pub result: Vec<Felt>,
+    pub execution_resources: ExecutionResources,
+}
+
+/// VM cost of a single [`FunctionInvocation`], as recorded by the Cairo VM while
+/// executing it. Mirrors `cairo_vm`'s own `ExecutionResources`, but keeps the
+/// builtin counters in a `BTreeMap` so they serialize deterministically.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExecutionResources {
+    pub n_steps: usize,
+    pub n_memory_holes: usize,
+    pub builtin_instance_counter: BTreeMap<BuiltinName, usize>,
+}
+
+impl ExecutionResources {
+    /// Sums the resources of `invocation` with those of every invocation in its
+    /// `internal_calls`, giving the total VM cost of the whole call tree.
+    pub fn aggregate(invocation: &FunctionInvocation) -> Self {
+        let mut total = invocation.execution_resources.clone();
+        for internal_call in &invocation.internal_calls {
+            let child_total = Self::aggregate(internal_call);
+            total.n_steps += child_total.n_steps;
+            total.n_memory_holes += child_total.n_memory_holes;
+            for (builtin, count) in child_total.builtin_instance_counter {
+                *total.builtin_instance_counter.entry(builtin).or_default() += count;
+            }
+        }
+        total
+    }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+impl From<&cairo_vm::vm::runners::cairo_runner::ExecutionResources> for ExecutionResources {
+    fn from(resources: &cairo_vm::vm::runners::cairo_runner::ExecutionResources) -> Self {
+        Self {
+            n_steps: resources.n_steps,
+            n_memory_holes: resources.n_memory_holes,
+            builtin_instance_counter: resources
+                .builtin_instance_counter
+                .iter()
+                .map(|(builtin, count)| (*builtin, *count))
+                .collect(),
+        }
+    }
+}
+
+#[serde_as]
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct MsgToL1 {
     pub order: usize,
+    #[serde_as(as = "Vec<pathfinder_serde::FeltAsHexStr>")]
     pub payload: Vec<Felt>,
+    #[serde_as(as = "pathfinder_serde::FeltAsHexStr")]
     pub to_address: Felt,
+    #[serde_as(as = "pathfinder_serde::FeltAsHexStr")]
     pub from_address: Felt,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct StateDiff {
     pub storage_diffs: BTreeMap<ContractAddress, Vec<StorageDiff>>,
     pub deployed_contracts: Vec<DeployedContract>,
@@ -117,25 +245,25 @@ pub struct StateDiff {
     pub replaced_classes: Vec<ReplacedClass>,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct StorageDiff {
     pub key: StorageAddress,
     pub value: StorageValue,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct DeployedContract {
     pub address: ContractAddress,
     pub class_hash: ClassHash,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct DeclaredSierraClass {
     pub class_hash: SierraHash,
     pub compiled_class_hash: CasmHash,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct ReplacedClass {
     pub contract_address: ContractAddress,
     pub class_hash: ClassHash,
@@ -194,6 +322,7 @@ impl TryFrom<blockifier::execution::entry_point::CallInfo> for FunctionInvocatio
             events,
             messages,
             result,
+            execution_resources: (&call_info.resources).into(),
         })
     }
 }
@@ -262,3 +391,793 @@ fn ordered_l2_to_l1_messages(
 
     messages.into_values().collect()
 }
+
+/// Traces every transaction in `transactions`, in order, against a single
+/// shared `state`.
+///
+/// Unlike [`TransactionSimulation`], which re-executes a transaction against
+/// an independent copy of the pre-state, this executes the whole batch
+/// sequentially so that transaction `i` observes the state changes made by
+/// transactions `0..i` -- matching how a block is actually executed. This
+/// underpins `starknet_traceBlockTransactions`.
+///
+/// A reverted `Invoke` still produces a trace (with
+/// [`ExecuteInvocation::RevertedReason`]) instead of aborting the batch; any
+/// other execution failure is propagated and stops the batch early.
+///
+/// Each trace is paired with an [`EventBloom`] built from it, so a
+/// block-level `getEvents` query can skip a transaction's events entirely
+/// when its bloom misses, instead of re-scanning every trace in the block.
+pub fn trace_block_transactions<S: StateReader>(
+    state: &mut CachedState<S>,
+    block_context: &BlockContext,
+    transactions: Vec<(TransactionHash, ExecutableTransaction)>,
+) -> Result<Vec<(TransactionHash, TransactionTrace, EventBloom)>, TransactionExecutionError> {
+    let mut traces = Vec::with_capacity(transactions.len());
+
+    for (transaction_hash, transaction) in transactions {
+        // Execute against a transactional overlay so this transaction's state
+        // diff can be isolated from its predecessors', then fold the overlay
+        // back into the shared state before moving on.
+        let mut tx_state = CachedState::create_transactional(state);
+
+        let declared_class = declared_class(&transaction);
+        let kind = TxKind::from(&transaction);
+        // `Transaction::execute` takes `self` by value, so `transaction` is
+        // gone after this call -- `kind` is what the match below dispatches
+        // on instead of the (now moved-from) `transaction`.
+        let execution_info = transaction.execute(&mut tx_state, block_context, true, true)?;
+        let state_diff = state_diff(&tx_state, declared_class)?;
+        tx_state.commit();
+
+        let trace = match kind {
+            TxKind::Declare => {
+                TransactionTrace::Declare(DeclareTransactionTrace {
+                    validate_invocation: execution_info
+                        .validate_call_info
+                        .map(TryInto::try_into)
+                        .transpose()?,
+                    fee_transfer_invocation: execution_info
+                        .fee_transfer_call_info
+                        .map(TryInto::try_into)
+                        .transpose()?,
+                    state_diff,
+                })
+            }
+            TxKind::DeployAccount => {
+                TransactionTrace::DeployAccount(DeployAccountTransactionTrace {
+                    validate_invocation: execution_info
+                        .validate_call_info
+                        .map(TryInto::try_into)
+                        .transpose()?,
+                    constructor_invocation: execution_info
+                        .execute_call_info
+                        .map(TryInto::try_into)
+                        .transpose()?,
+                    fee_transfer_invocation: execution_info
+                        .fee_transfer_call_info
+                        .map(TryInto::try_into)
+                        .transpose()?,
+                    state_diff,
+                })
+            }
+            TxKind::Invoke => invoke_trace(
+                execution_info.validate_call_info,
+                execution_info.execute_call_info,
+                execution_info.fee_transfer_call_info,
+                execution_info.revert_error,
+                state_diff,
+            )?,
+            TxKind::L1Handler => TransactionTrace::L1Handler(L1HandlerTransactionTrace {
+                function_invocation: execution_info
+                    .execute_call_info
+                    .map(TryInto::try_into)
+                    .transpose()?,
+                state_diff,
+            }),
+        };
+
+        let bloom = EventBloom::from_trace(&trace);
+        traces.push((transaction_hash, trace, bloom));
+    }
+
+    Ok(traces)
+}
+
+/// Builds the [`TransactionTrace::Invoke`] variant out of one transaction's
+/// execution pieces. Split out of [`trace_block_transactions`] so the
+/// revert-vs-success branching can be unit tested without executing a real
+/// transaction: a reverted invoke must still produce a trace (with
+/// [`ExecuteInvocation::RevertedReason`]) rather than propagating an error
+/// and aborting the rest of the batch.
+fn invoke_trace(
+    validate_call_info: Option<blockifier::execution::entry_point::CallInfo>,
+    execute_call_info: Option<blockifier::execution::entry_point::CallInfo>,
+    fee_transfer_call_info: Option<blockifier::execution::entry_point::CallInfo>,
+    revert_error: Option<String>,
+    state_diff: StateDiff,
+) -> Result<TransactionTrace, TransactionExecutionError> {
+    let execute_invocation = match revert_error {
+        Some(reason) => ExecuteInvocation::RevertedReason(reason),
+        None => {
+            ExecuteInvocation::FunctionInvocation(execute_call_info.map(TryInto::try_into).transpose()?)
+        }
+    };
+
+    Ok(TransactionTrace::Invoke(InvokeTransactionTrace {
+        validate_invocation: validate_call_info.map(TryInto::try_into).transpose()?,
+        execute_invocation,
+        fee_transfer_invocation: fee_transfer_call_info.map(TryInto::try_into).transpose()?,
+        state_diff,
+    }))
+}
+
+/// Number of bits in an [`EventBloom`] filter.
+const EVENT_BLOOM_BITS: usize = 2048;
+/// Number of bytes backing an [`EventBloom`] filter.
+const EVENT_BLOOM_BYTES: usize = EVENT_BLOOM_BITS / 8;
+
+/// A fixed-size bloom filter over the `from_address` and `keys` of every
+/// [`Event`] in a [`TransactionTrace`], used to cheaply rule out transactions
+/// and blocks that can't match a `starknet_getEvents` address/key filter --
+/// the same log-bloom pruning trick Ethereum receipts use for
+/// `eth_getLogs`.
+///
+/// A transaction is added to the filter by `from_address` and each of its
+/// events' `keys`, each hashed and contributing three set bits. A negative
+/// [`EventBloom::maybe_contains`] result is conclusive; a positive one means
+/// the events must still be scanned to confirm an actual match.
+///
+/// Serializes as a `0x`-prefixed hex string, like the `Felt`/`U256` fields
+/// elsewhere in this module, rather than as a 256-element JSON array of
+/// integers -- this type exists to be cached on disk, so a compact
+/// representation matters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventBloom(Vec<u8>);
+
+impl Serialize for EventBloom {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&format_bloom_hex(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for EventBloom {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let hex = String::deserialize(deserializer)?;
+        let bytes = parse_bloom_hex(&hex).map_err(serde::de::Error::custom)?;
+
+        if bytes.len() != EVENT_BLOOM_BYTES {
+            return Err(serde::de::Error::custom(format!(
+                "expected a {EVENT_BLOOM_BYTES}-byte EventBloom, got {} bytes",
+                bytes.len()
+            )));
+        }
+
+        Ok(Self(bytes))
+    }
+}
+
+fn format_bloom_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 + bytes.len() * 2);
+    hex.push_str("0x");
+    for byte in bytes {
+        hex.push_str(&format!("{byte:02x}"));
+    }
+    hex
+}
+
+fn parse_bloom_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let digits = hex.strip_prefix("0x").unwrap_or(hex);
+    if digits.len() % 2 != 0 {
+        return Err(format!("hex string has an odd number of digits: {hex}"));
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|e| format!("invalid hex digit in {hex}: {e}"))
+        })
+        .collect()
+}
+
+impl EventBloom {
+    fn empty() -> Self {
+        Self(vec![0u8; EVENT_BLOOM_BYTES])
+    }
+
+    /// Builds a filter covering every event emitted anywhere in `trace`,
+    /// including from `internal_calls`.
+    pub fn from_trace(trace: &TransactionTrace) -> Self {
+        let mut bloom = Self::empty();
+        for invocation in trace.root_invocations() {
+            bloom.insert_invocation(invocation);
+        }
+        bloom
+    }
+
+    /// Returns `false` only when `address`/`keys` definitely don't appear
+    /// together in the trace this filter was built from.
+    pub fn maybe_contains(&self, address: ContractAddress, keys: &[Felt]) -> bool {
+        self.contains_felt(address.get()) && keys.iter().all(|key| self.contains_felt(*key))
+    }
+
+    fn insert_invocation(&mut self, invocation: &FunctionInvocation) {
+        for event in &invocation.events {
+            self.insert_felt(invocation.contract_address.get());
+            for key in &event.keys {
+                self.insert_felt(*key);
+            }
+        }
+        for internal_call in &invocation.internal_calls {
+            self.insert_invocation(internal_call);
+        }
+    }
+
+    fn insert_felt(&mut self, felt: Felt) {
+        for bit in bloom_bit_indices(felt) {
+            self.0[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    fn contains_felt(&self, felt: Felt) -> bool {
+        bloom_bit_indices(felt)
+            .into_iter()
+            .all(|bit| self.0[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+}
+
+/// Three disjoint 11-bit windows of `felt`'s hash, each in `0..EVENT_BLOOM_BITS`.
+///
+/// Blooms are computed once and persisted alongside their trace, then
+/// re-derived from a queried address/key to test membership later --
+/// possibly in a different process or after a toolchain upgrade. That rules
+/// out `std::collections::hash_map::DefaultHasher`, whose algorithm std
+/// explicitly does not guarantee to stay the same across Rust releases; a
+/// silent change there would turn old blooms into false negatives. FNV-1a is
+/// a fixed, unversioned algorithm, so a bloom written today stays readable
+/// forever.
+fn bloom_bit_indices(felt: Felt) -> [usize; 3] {
+    let hash = fnv1a_hash(&felt.to_be_bytes());
+
+    [
+        (hash & 0x7ff) as usize,
+        ((hash >> 11) & 0x7ff) as usize,
+        ((hash >> 22) & 0x7ff) as usize,
+    ]
+}
+
+/// FNV-1a, 64-bit variant, with the algorithm's standard offset basis and
+/// prime -- fixed constants, not a seed we control, so the hash is stable
+/// across Rust versions and processes.
+fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash = FNV_OFFSET_BASIS;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+impl TransactionTrace {
+    /// The top-level [`FunctionInvocation`]s of this trace -- `validate`,
+    /// `execute`/`constructor`, and `fee_transfer` -- from which the rest of
+    /// the invocation tree can be reached via `internal_calls`.
+    fn root_invocations(&self) -> Vec<&FunctionInvocation> {
+        let mut roots = Vec::new();
+
+        match self {
+            TransactionTrace::Declare(t) => {
+                roots.extend(t.validate_invocation.iter());
+                roots.extend(t.fee_transfer_invocation.iter());
+            }
+            TransactionTrace::DeployAccount(t) => {
+                roots.extend(t.validate_invocation.iter());
+                roots.extend(t.constructor_invocation.iter());
+                roots.extend(t.fee_transfer_invocation.iter());
+            }
+            TransactionTrace::Invoke(t) => {
+                roots.extend(t.validate_invocation.iter());
+                if let ExecuteInvocation::FunctionInvocation(Some(invocation)) =
+                    &t.execute_invocation
+                {
+                    roots.push(invocation);
+                }
+                roots.extend(t.fee_transfer_invocation.iter());
+            }
+            TransactionTrace::L1Handler(t) => {
+                roots.extend(t.function_invocation.iter());
+            }
+        }
+
+        roots
+    }
+}
+
+/// Which [`TransactionTrace`] variant a transaction produces. `Copy` so it
+/// can be taken from `&transaction` and still matched on after `transaction`
+/// itself has been moved into `Transaction::execute`.
+#[derive(Copy, Clone)]
+enum TxKind {
+    Declare,
+    DeployAccount,
+    Invoke,
+    L1Handler,
+}
+
+impl From<&ExecutableTransaction> for TxKind {
+    fn from(transaction: &ExecutableTransaction) -> Self {
+        match transaction {
+            ExecutableTransaction::AccountTransaction(AccountTransaction::Declare(_)) => {
+                TxKind::Declare
+            }
+            ExecutableTransaction::AccountTransaction(AccountTransaction::DeployAccount(_)) => {
+                TxKind::DeployAccount
+            }
+            ExecutableTransaction::AccountTransaction(AccountTransaction::Invoke(_)) => {
+                TxKind::Invoke
+            }
+            ExecutableTransaction::L1HandlerTransaction(_) => TxKind::L1Handler,
+        }
+    }
+}
+
+/// The class a `Declare` transaction introduces, if any, split by Cairo
+/// version since the two are tracked in separate `StateDiff` fields.
+fn declared_class(transaction: &ExecutableTransaction) -> Option<DeclaredClass> {
+    let ExecutableTransaction::AccountTransaction(AccountTransaction::Declare(declare)) =
+        transaction
+    else {
+        return None;
+    };
+
+    let class_hash = declare.class_hash().0.into_felt();
+
+    match declare.compiled_class_hash() {
+        Some(compiled_class_hash) => Some(DeclaredClass::Sierra(DeclaredSierraClass {
+            class_hash: SierraHash(class_hash),
+            compiled_class_hash: CasmHash(compiled_class_hash.0.into_felt()),
+        })),
+        None => Some(DeclaredClass::Deprecated(ClassHash(class_hash))),
+    }
+}
+
+enum DeclaredClass {
+    Sierra(DeclaredSierraClass),
+    Deprecated(ClassHash),
+}
+
+/// Builds this transaction's own [`StateDiff`] out of its transactional
+/// overlay, reading the class hash from the state underneath the overlay
+/// (`tx_state.state`) to tell a fresh contract deployment apart from a class
+/// replacement.
+fn state_diff<S: StateReader>(
+    tx_state: &CachedState<S>,
+    declared_class: Option<DeclaredClass>,
+) -> Result<StateDiff, TransactionExecutionError> {
+    let diff = tx_state.to_state_diff()?;
+
+    let mut storage_diffs: BTreeMap<ContractAddress, Vec<StorageDiff>> = BTreeMap::new();
+    for ((address, key), value) in diff.storage_updates {
+        let address = ContractAddress::new_or_panic(address.0.key().into_felt());
+        storage_diffs.entry(address).or_default().push(StorageDiff {
+            key: StorageAddress::new_or_panic(key.0.key().into_felt()),
+            value: StorageValue(value.into_felt()),
+        });
+    }
+
+    let mut deployed_contracts = Vec::new();
+    let mut replaced_classes = Vec::new();
+    for (api_address, class_hash) in diff.address_to_class_hash {
+        let previous_class_hash = tx_state.state.get_class_hash_at(api_address)?;
+        let address = ContractAddress::new_or_panic(api_address.0.key().into_felt());
+        let class_hash = ClassHash(class_hash.0.into_felt());
+
+        if previous_class_hash.0.into_felt() == Felt::ZERO {
+            deployed_contracts.push(DeployedContract {
+                address,
+                class_hash,
+            });
+        } else {
+            replaced_classes.push(ReplacedClass {
+                contract_address: address,
+                class_hash,
+            });
+        }
+    }
+
+    let nonces = diff
+        .address_to_nonce
+        .into_iter()
+        .map(|(address, nonce)| {
+            (
+                ContractAddress::new_or_panic(address.0.key().into_felt()),
+                ContractNonce(nonce.0.into_felt()),
+            )
+        })
+        .collect();
+
+    let mut declared_classes = Vec::new();
+    let mut deprecated_declared_classes = HashSet::new();
+    match declared_class {
+        Some(DeclaredClass::Sierra(class)) => declared_classes.push(class),
+        Some(DeclaredClass::Deprecated(class_hash)) => {
+            deprecated_declared_classes.insert(class_hash);
+        }
+        None => {}
+    }
+
+    Ok(StateDiff {
+        storage_diffs,
+        deployed_contracts,
+        deprecated_declared_classes,
+        declared_classes,
+        nonces,
+        replaced_classes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn felt(byte: u8) -> Felt {
+        Felt::from_be_slice(&[byte]).unwrap()
+    }
+
+    fn sample_trace() -> TransactionTrace {
+        let contract_address = ContractAddress::new_or_panic(felt(1));
+
+        let inner_invocation = FunctionInvocation {
+            calldata: vec![felt(2)],
+            contract_address,
+            selector: felt(3),
+            call_type: CallType::Delegate,
+            caller_address: felt(4),
+            internal_calls: vec![],
+            class_hash: Some(felt(5)),
+            entry_point_type: EntryPointType::External,
+            events: vec![Event {
+                order: 0,
+                data: vec![felt(6)],
+                keys: vec![felt(7)],
+            }],
+            messages: vec![MsgToL1 {
+                order: 0,
+                payload: vec![felt(8)],
+                to_address: felt(9),
+                from_address: felt(10),
+            }],
+            result: vec![felt(11)],
+            execution_resources: ExecutionResources {
+                n_steps: 10,
+                n_memory_holes: 1,
+                builtin_instance_counter: BTreeMap::from([(BuiltinName::range_check, 2)]),
+            },
+        };
+
+        let outer_invocation = FunctionInvocation {
+            calldata: vec![],
+            contract_address,
+            selector: felt(12),
+            call_type: CallType::Call,
+            caller_address: felt(13),
+            internal_calls: vec![inner_invocation],
+            class_hash: None,
+            entry_point_type: EntryPointType::Constructor,
+            events: vec![],
+            messages: vec![],
+            result: vec![],
+            execution_resources: ExecutionResources {
+                n_steps: 100,
+                n_memory_holes: 5,
+                builtin_instance_counter: BTreeMap::from([(BuiltinName::pedersen, 3)]),
+            },
+        };
+
+        let mut storage_diffs = BTreeMap::new();
+        storage_diffs.insert(
+            contract_address,
+            vec![StorageDiff {
+                key: StorageAddress::new_or_panic(felt(14)),
+                value: StorageValue(felt(15)),
+            }],
+        );
+
+        let mut deprecated_declared_classes = HashSet::new();
+        deprecated_declared_classes.insert(ClassHash(felt(16)));
+
+        let mut nonces = BTreeMap::new();
+        nonces.insert(contract_address, ContractNonce(felt(17)));
+
+        let state_diff = StateDiff {
+            storage_diffs,
+            deployed_contracts: vec![DeployedContract {
+                address: contract_address,
+                class_hash: ClassHash(felt(18)),
+            }],
+            deprecated_declared_classes,
+            declared_classes: vec![DeclaredSierraClass {
+                class_hash: SierraHash(felt(19)),
+                compiled_class_hash: CasmHash(felt(20)),
+            }],
+            nonces,
+            replaced_classes: vec![ReplacedClass {
+                contract_address,
+                class_hash: ClassHash(felt(21)),
+            }],
+        };
+
+        TransactionTrace::Invoke(InvokeTransactionTrace {
+            validate_invocation: Some(outer_invocation),
+            execute_invocation: ExecuteInvocation::RevertedReason("reverted".to_string()),
+            fee_transfer_invocation: None,
+            state_diff,
+        })
+    }
+
+    #[test]
+    fn transaction_trace_round_trips_through_json() {
+        let trace = sample_trace();
+
+        let serialized = serde_json::to_vec(&trace).unwrap();
+        let deserialized: TransactionTrace = serde_json::from_slice(&serialized).unwrap();
+
+        // `TransactionTrace` and its invocation types don't implement `PartialEq`,
+        // so round-trip equality is checked via their `Debug` representation instead.
+        assert_eq!(format!("{trace:?}"), format!("{deserialized:?}"));
+    }
+
+    #[test]
+    fn fee_estimate_round_trips_through_json() {
+        let fee_estimate = FeeEstimate::from_resource_prices(
+            primitive_types::U256::from(123u64),
+            primitive_types::U256::from(456u64),
+            primitive_types::U256::from(7u64),
+            primitive_types::U256::from(8u64),
+            PriceUnit::Fri,
+        );
+
+        let serialized = serde_json::to_vec(&fee_estimate).unwrap();
+        let deserialized: FeeEstimate = serde_json::from_slice(&serialized).unwrap();
+
+        assert_eq!(fee_estimate.gas_consumed, deserialized.gas_consumed);
+        assert_eq!(fee_estimate.gas_price, deserialized.gas_price);
+        assert_eq!(fee_estimate.data_gas_consumed, deserialized.data_gas_consumed);
+        assert_eq!(fee_estimate.data_gas_price, deserialized.data_gas_price);
+        assert_eq!(fee_estimate.overall_fee, deserialized.overall_fee);
+        assert_eq!(fee_estimate.unit, deserialized.unit);
+    }
+
+    #[test]
+    fn fee_estimate_overall_fee_sums_gas_and_data_gas() {
+        let fee_estimate = FeeEstimate::from_resource_prices(
+            primitive_types::U256::from(123u64),
+            primitive_types::U256::from(456u64),
+            primitive_types::U256::from(7u64),
+            primitive_types::U256::from(8u64),
+            PriceUnit::Wei,
+        );
+
+        assert_eq!(
+            fee_estimate.overall_fee,
+            primitive_types::U256::from(123u64 * 456 + 7 * 8)
+        );
+    }
+
+    #[test]
+    fn execution_resources_aggregate_sums_internal_calls() {
+        let TransactionTrace::Invoke(InvokeTransactionTrace {
+            validate_invocation: Some(outer_invocation),
+            ..
+        }) = sample_trace()
+        else {
+            panic!("expected an invoke trace with a validate invocation");
+        };
+
+        let aggregated = ExecutionResources::aggregate(&outer_invocation);
+
+        assert_eq!(aggregated.n_steps, 110);
+        assert_eq!(aggregated.n_memory_holes, 6);
+        assert_eq!(aggregated.builtin_instance_counter[&BuiltinName::pedersen], 3);
+        assert_eq!(
+            aggregated.builtin_instance_counter[&BuiltinName::range_check],
+            2
+        );
+    }
+
+    #[test]
+    fn event_bloom_matches_events_in_trace() {
+        let trace = sample_trace();
+        let bloom = EventBloom::from_trace(&trace);
+
+        let contract_address = ContractAddress::new_or_panic(felt(1));
+
+        assert!(bloom.maybe_contains(contract_address, &[felt(7)]));
+        assert!(!bloom.maybe_contains(contract_address, &[felt(99)]));
+        assert!(!bloom.maybe_contains(ContractAddress::new_or_panic(felt(99)), &[felt(7)]));
+    }
+
+    fn empty_state_diff() -> StateDiff {
+        StateDiff {
+            storage_diffs: BTreeMap::new(),
+            deployed_contracts: Vec::new(),
+            deprecated_declared_classes: HashSet::new(),
+            declared_classes: Vec::new(),
+            nonces: BTreeMap::new(),
+            replaced_classes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn invoke_trace_reports_revert_instead_of_erroring() {
+        let trace = invoke_trace(
+            None,
+            None,
+            None,
+            Some("insufficient balance".to_string()),
+            empty_state_diff(),
+        )
+        .unwrap();
+
+        let TransactionTrace::Invoke(InvokeTransactionTrace {
+            execute_invocation, ..
+        }) = trace
+        else {
+            panic!("expected an invoke trace");
+        };
+
+        assert!(matches!(
+            execute_invocation,
+            ExecuteInvocation::RevertedReason(reason) if reason == "insufficient balance"
+        ));
+    }
+
+    #[test]
+    fn invoke_trace_reports_success_when_not_reverted() {
+        let trace = invoke_trace(None, None, None, None, empty_state_diff()).unwrap();
+
+        let TransactionTrace::Invoke(InvokeTransactionTrace {
+            execute_invocation, ..
+        }) = trace
+        else {
+            panic!("expected an invoke trace");
+        };
+
+        assert!(matches!(
+            execute_invocation,
+            ExecuteInvocation::FunctionInvocation(None)
+        ));
+    }
+
+    /// A [`StateReader`] over a fixed, never-written class hash assignment,
+    /// used only to tell `state_diff`'s deployed-vs-replaced check apart in
+    /// tests -- storage, nonce and compiled-class-hash reads return
+    /// blockifier's default value, and compiled-class lookups report the
+    /// class as undeclared.
+    struct FixedClassHashReader {
+        class_hashes: std::collections::HashMap<
+            starknet_api::core::ContractAddress,
+            starknet_api::core::ClassHash,
+        >,
+    }
+
+    impl StateReader for FixedClassHashReader {
+        fn get_storage_at(
+            &self,
+            _contract_address: starknet_api::core::ContractAddress,
+            _key: starknet_api::state::StorageKey,
+        ) -> blockifier::state::state_api::StateResult<starknet_api::hash::StarkFelt> {
+            Ok(starknet_api::hash::StarkFelt::default())
+        }
+
+        fn get_nonce_at(
+            &self,
+            _contract_address: starknet_api::core::ContractAddress,
+        ) -> blockifier::state::state_api::StateResult<starknet_api::core::Nonce> {
+            Ok(starknet_api::core::Nonce::default())
+        }
+
+        fn get_class_hash_at(
+            &self,
+            contract_address: starknet_api::core::ContractAddress,
+        ) -> blockifier::state::state_api::StateResult<starknet_api::core::ClassHash> {
+            Ok(self
+                .class_hashes
+                .get(&contract_address)
+                .copied()
+                .unwrap_or_default())
+        }
+
+        fn get_compiled_contract_class(
+            &self,
+            class_hash: starknet_api::core::ClassHash,
+        ) -> blockifier::state::state_api::StateResult<blockifier::execution::contract_class::ContractClass>
+        {
+            Err(blockifier::state::errors::StateError::UndeclaredClassHash(
+                class_hash,
+            ))
+        }
+
+        fn get_compiled_class_hash(
+            &self,
+            _class_hash: starknet_api::core::ClassHash,
+        ) -> blockifier::state::state_api::StateResult<starknet_api::core::CompiledClassHash> {
+            Ok(starknet_api::core::CompiledClassHash::default())
+        }
+    }
+
+    fn api_contract_address(byte: u8) -> starknet_api::core::ContractAddress {
+        starknet_api::core::ContractAddress::try_from(starknet_api::hash::StarkFelt::from(
+            byte as u64,
+        ))
+        .unwrap()
+    }
+
+    fn api_storage_key(byte: u8) -> starknet_api::state::StorageKey {
+        starknet_api::state::StorageKey::try_from(starknet_api::hash::StarkFelt::from(
+            byte as u64,
+        ))
+        .unwrap()
+    }
+
+    /// Reproduces `trace_block_transactions`'s own sequencing -- a
+    /// transactional overlay per transaction, committed into the shared
+    /// state before the next one starts -- and checks that transaction 2's
+    /// `StateDiff` contains only transaction 2's own write, not transaction
+    /// 1's, even though both ran against the same underlying `CachedState`.
+    #[test]
+    fn state_diff_is_isolated_per_transaction() {
+        let address = api_contract_address(1);
+        let key_one = api_storage_key(1);
+        let key_two = api_storage_key(2);
+
+        let reader = FixedClassHashReader {
+            class_hashes: std::collections::HashMap::new(),
+        };
+        let mut state = CachedState::new(reader);
+
+        // Transaction 1 writes `key_one`.
+        {
+            let mut tx_state = CachedState::create_transactional(&mut state);
+            tx_state
+                .set_storage_at(address, key_one, starknet_api::hash::StarkFelt::from(10u64))
+                .unwrap();
+            let _ = state_diff(&tx_state, None).unwrap();
+            tx_state.commit();
+        }
+
+        // Transaction 2 writes `key_two`; its diff must not include `key_one`,
+        // even though transaction 1's write is now visible in `state`.
+        let tx2_diff = {
+            let mut tx_state = CachedState::create_transactional(&mut state);
+            tx_state
+                .set_storage_at(address, key_two, starknet_api::hash::StarkFelt::from(20u64))
+                .unwrap();
+            let diff = state_diff(&tx_state, None).unwrap();
+            tx_state.commit();
+            diff
+        };
+
+        let pathfinder_address = ContractAddress::new_or_panic(address.0.key().into_felt());
+        let storage_diffs = tx2_diff
+            .storage_diffs
+            .get(&pathfinder_address)
+            .expect("transaction 2 wrote to this contract");
+
+        let keys: Vec<_> = storage_diffs.iter().map(|diff| diff.key).collect();
+        assert_eq!(
+            keys,
+            vec![StorageAddress::new_or_panic(key_two.0.key().into_felt())]
+        );
+    }
+}